@@ -2,12 +2,37 @@
  *  Copyright (c) Microsoft Corporation. All rights reserved.
  *  Licensed under the MIT License. See License.txt in the project root for license information.
  *--------------------------------------------------------------------------------------------*/
-use std::{io, task::Poll};
+use std::{
+	io,
+	pin::Pin,
+	task::{Context, Poll},
+	time::{Duration, Instant},
+};
 
-use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use futures::Stream;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf};
 
 pub trait ReportCopyProgress {
 	fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64);
+
+	/// Like `report_progress`, but also given the time elapsed since the
+	/// copy started. Implementors can use this, together with the bytes
+	/// and elapsed time from previous calls, to derive a smoothed transfer
+	/// rate (e.g. an exponentially-weighted moving average of the
+	/// instantaneous rate between calls, `new_rate = α·sample + (1−α)·old_rate`
+	/// with `α≈0.3`) and an ETA of `(total_bytes - bytes_so_far) / rate`,
+	/// guarding against a zero rate before any data has moved.
+	///
+	/// NOTE: this is plumbing only — the copy machinery just threads
+	/// `elapsed` through to whichever reporter is in use. No reporter in
+	/// this crate implements the EWMA smoothing or ETA math described
+	/// above yet; that's left to whatever UI wants to render a rate/ETA.
+	///
+	/// The default implementation just forwards to `report_progress`, for
+	/// reporters that don't care about rate/ETA.
+	fn report_progress_detailed(&mut self, bytes_so_far: u64, total_bytes: u64, _elapsed: Duration) {
+		self.report_progress(bytes_so_far, total_bytes);
+	}
 }
 
 /// Type that doesn't emit anything for download progress.
@@ -17,8 +42,20 @@ impl ReportCopyProgress for SilentCopyProgress {
 	fn report_progress(&mut self, _bytes_so_far: u64, _total_bytes: u64) {}
 }
 
+/// Initial size of the read buffer used by `copy_async_progress`.
+const INITIAL_COPY_BUF_SIZE: usize = 8 * 1024;
+
+/// Largest size the read buffer used by `copy_async_progress` will grow to.
+const MAX_COPY_BUF_SIZE: usize = 1024 * 1024;
+
 /// Copies from the reader to the writer, reporting progress to the provided
 /// reporter every so often.
+///
+/// The read buffer starts small and doubles in size, up to
+/// `MAX_COPY_BUF_SIZE`, each time a read completely fills it, mirroring
+/// tokio's adaptive buffer strategy. This lets large, fast transfers ramp up
+/// to big reads/writes for higher throughput, while small transfers never
+/// over-allocate.
 pub async fn copy_async_progress<T, R, W>(
 	mut reporter: T,
 	reader: &mut R,
@@ -30,12 +67,13 @@ where
 	W: AsyncWrite + Unpin,
 	T: ReportCopyProgress,
 {
-	let mut buf = vec![0; 8 * 1024];
+	let mut buf = vec![0; INITIAL_COPY_BUF_SIZE];
 	let mut bytes_so_far = 0;
 	let mut bytes_last_reported = 0;
 	let report_granularity = std::cmp::min(total_bytes / 10, 2 * 1024 * 1024);
+	let start = Instant::now();
 
-	reporter.report_progress(0, total_bytes);
+	reporter.report_progress_detailed(0, total_bytes, start.elapsed());
 
 	loop {
 		let read_buf = match reader.read(&mut buf).await {
@@ -44,20 +82,255 @@ where
 			Err(e) => return Err(e),
 		};
 
+		let n = read_buf.len();
 		writer.write_all(read_buf).await?;
 
-		bytes_so_far += read_buf.len() as u64;
+		bytes_so_far += n as u64;
 		if bytes_so_far - bytes_last_reported > report_granularity {
 			bytes_last_reported = bytes_so_far;
-			reporter.report_progress(bytes_so_far, total_bytes);
+			reporter.report_progress_detailed(bytes_so_far, total_bytes, start.elapsed());
+		}
+
+		if n == buf.len() && buf.len() < MAX_COPY_BUF_SIZE {
+			let new_len = std::cmp::min(buf.len() * 2, MAX_COPY_BUF_SIZE);
+			buf.resize(new_len, 0);
 		}
 	}
 
-	reporter.report_progress(bytes_so_far, total_bytes);
+	reporter.report_progress_detailed(bytes_so_far, total_bytes, start.elapsed());
 
 	Ok(bytes_so_far)
 }
 
+/// State of a single direction of a `copy_bidirectional_progress` transfer.
+enum TransferState {
+	Running(CopyBuffer),
+	ShuttingDown(u64),
+	Done(u64),
+}
+
+impl TransferState {
+	/// Bytes copied so far in this direction, regardless of whether the
+	/// transfer has finished.
+	fn amt(&self) -> u64 {
+		match self {
+			TransferState::Running(buf) => buf.amt,
+			TransferState::ShuttingDown(amt) => *amt,
+			TransferState::Done(amt) => *amt,
+		}
+	}
+}
+
+/// Buffer and bookkeeping for copying one direction of a bidirectional copy,
+/// mirroring tokio's internal `CopyBuffer`.
+struct CopyBuffer {
+	read_done: bool,
+	pos: usize,
+	cap: usize,
+	amt: u64,
+	buf: Box<[u8]>,
+}
+
+impl CopyBuffer {
+	fn new() -> Self {
+		Self {
+			read_done: false,
+			pos: 0,
+			cap: 0,
+			amt: 0,
+			buf: vec![0; 8 * 1024].into_boxed_slice(),
+		}
+	}
+
+	fn poll_copy<R, W>(
+		&mut self,
+		cx: &mut Context<'_>,
+		mut reader: Pin<&mut R>,
+		mut writer: Pin<&mut W>,
+	) -> Poll<io::Result<u64>>
+	where
+		R: AsyncRead + ?Sized,
+		W: AsyncWrite + ?Sized,
+	{
+		loop {
+			if self.pos == self.cap && !self.read_done {
+				let mut read_buf = ReadBuf::new(&mut self.buf);
+				std::task::ready!(reader.as_mut().poll_read(cx, &mut read_buf))?;
+
+				let n = read_buf.filled().len();
+				if n == 0 {
+					self.read_done = true;
+				} else {
+					self.pos = 0;
+					self.cap = n;
+				}
+			}
+
+			while self.pos < self.cap {
+				let n =
+					std::task::ready!(writer.as_mut().poll_write(cx, &self.buf[self.pos..self.cap]))?;
+				if n == 0 {
+					return Poll::Ready(Err(io::Error::new(
+						io::ErrorKind::WriteZero,
+						"write zero byte into writer",
+					)));
+				}
+				self.pos += n;
+				self.amt += n as u64;
+			}
+
+			if self.pos == self.cap && self.read_done {
+				std::task::ready!(writer.as_mut().poll_flush(cx))?;
+				return Poll::Ready(Ok(self.amt));
+			}
+		}
+	}
+}
+
+/// Drives one direction of a bidirectional copy to completion, shutting
+/// down the writer once the reader hits EOF.
+fn poll_transfer_one_direction<R, W>(
+	cx: &mut Context<'_>,
+	state: &mut TransferState,
+	mut reader: Pin<&mut R>,
+	mut writer: Pin<&mut W>,
+) -> Poll<io::Result<u64>>
+where
+	R: AsyncRead + ?Sized,
+	W: AsyncWrite + ?Sized,
+{
+	loop {
+		match state {
+			TransferState::Running(buf) => {
+				let amt = std::task::ready!(buf.poll_copy(cx, reader.as_mut(), writer.as_mut()))?;
+				*state = TransferState::ShuttingDown(amt);
+			}
+			TransferState::ShuttingDown(amt) => {
+				std::task::ready!(writer.as_mut().poll_shutdown(cx))?;
+				*state = TransferState::Done(*amt);
+			}
+			TransferState::Done(amt) => return Poll::Ready(Ok(*amt)),
+		}
+	}
+}
+
+/// Copies data in both directions between `a` and `b` until both sides have
+/// completed, like tokio's `copy_bidirectional`, reporting the combined
+/// bytes transferred so far (in either direction) to `reporter` as the
+/// transfer progresses. Used for tunneled/forwarded streams where there's
+/// no single reader/writer pair and no fixed total size, so `total_bytes`
+/// is always reported as `0`.
+///
+/// When one direction reaches EOF its writer is shut down, but the other
+/// direction keeps being pumped until it also completes. Returns the total
+/// bytes copied in each direction as `(a_to_b, b_to_a)`.
+pub async fn copy_bidirectional_progress<A, B, T>(
+	a: &mut A,
+	b: &mut B,
+	mut reporter: T,
+) -> io::Result<(u64, u64)>
+where
+	A: AsyncRead + AsyncWrite + Unpin + ?Sized,
+	B: AsyncRead + AsyncWrite + Unpin + ?Sized,
+	T: ReportCopyProgress,
+{
+	let mut a_to_b = TransferState::Running(CopyBuffer::new());
+	let mut b_to_a = TransferState::Running(CopyBuffer::new());
+	let start = Instant::now();
+	let mut last_report = start;
+
+	std::future::poll_fn(|cx| {
+		let a_pin = Pin::new(&mut *a);
+		let b_pin = Pin::new(&mut *b);
+
+		let a_to_b_poll = poll_transfer_one_direction(cx, &mut a_to_b, a_pin, b_pin);
+		let a_pin = Pin::new(&mut *a);
+		let b_pin = Pin::new(&mut *b);
+		let b_to_a_poll = poll_transfer_one_direction(cx, &mut b_to_a, b_pin, a_pin);
+
+		// Throttle like `ProgressRead` does, so a busy tunnel with many small
+		// reads/writes doesn't flood the reporter on every poll.
+		if last_report.elapsed() >= PROGRESS_REPORT_INTERVAL {
+			last_report = Instant::now();
+			reporter.report_progress_detailed(a_to_b.amt() + b_to_a.amt(), 0, start.elapsed());
+		}
+
+		let a_to_b_amt = std::task::ready!(a_to_b_poll)?;
+		let b_to_a_amt = std::task::ready!(b_to_a_poll)?;
+
+		reporter.report_progress_detailed(a_to_b_amt + b_to_a_amt, 0, start.elapsed());
+
+		Poll::Ready(Ok((a_to_b_amt, b_to_a_amt)))
+	})
+	.await
+}
+
+/// Minimum time between progress reports emitted by `ProgressRead`, so that
+/// fast transfers with many small reads don't flood the reporter.
+const PROGRESS_REPORT_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Wraps an `AsyncRead` and reports progress to a `ReportCopyProgress` as
+/// bytes are read through it, without requiring the caller to drive a copy
+/// loop itself. Useful when the reader is buried in the middle of a
+/// pipeline (e.g. an HTTP body feeding a gzip decoder feeding a tar
+/// extractor) where `copy_async_progress` can't be used directly.
+///
+/// Reports are throttled to once per `PROGRESS_REPORT_INTERVAL`, with a
+/// final report always emitted once the inner reader reaches EOF.
+pub struct ProgressRead<R, T> {
+	inner: R,
+	reporter: T,
+	total_bytes: u64,
+	bytes_so_far: u64,
+	start: Instant,
+	last_report: Instant,
+}
+
+impl<R, T> ProgressRead<R, T> {
+	pub fn new(inner: R, total_bytes: u64, reporter: T) -> Self {
+		let now = Instant::now();
+		Self {
+			inner,
+			reporter,
+			total_bytes,
+			bytes_so_far: 0,
+			start: now,
+			last_report: now,
+		}
+	}
+}
+
+impl<R, T> AsyncRead for ProgressRead<R, T>
+where
+	R: AsyncRead + Unpin,
+	T: ReportCopyProgress + Unpin,
+{
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		let filled_before = buf.filled().len();
+		let result = Pin::new(&mut self.inner).poll_read(cx, buf);
+
+		if let Poll::Ready(Ok(())) = &result {
+			let n = buf.filled().len() - filled_before;
+			self.bytes_so_far += n as u64;
+
+			if n == 0 || self.last_report.elapsed() >= PROGRESS_REPORT_INTERVAL {
+				self.last_report = Instant::now();
+				self.reporter.report_progress_detailed(
+					self.bytes_so_far,
+					self.total_bytes,
+					self.start.elapsed(),
+				);
+			}
+		}
+
+		result
+	}
+}
+
 /// Helper used when converting Future interfaces to poll-based interfaces.
 /// Stores excess data that can be reused on future polls.
 #[derive(Default)]
@@ -95,3 +368,276 @@ impl ReadBuffer {
 		Poll::Ready(Ok(()))
 	}
 }
+
+/// Adapts a `Stream` of byte chunks into an `AsyncRead`, using a
+/// `ReadBuffer` to stash any part of a chunk that doesn't fit into the
+/// caller's buffer on a given poll. Useful for bridging things like an HTTP
+/// chunk stream or a decrypted-frame stream into the `AsyncRead`-based
+/// copy/progress machinery without reinventing the stashing at each call
+/// site.
+pub struct StreamReader<S> {
+	stream: S,
+	buffer: ReadBuffer,
+}
+
+impl<S> StreamReader<S> {
+	pub fn new(stream: S) -> Self {
+		Self {
+			stream,
+			buffer: ReadBuffer::default(),
+		}
+	}
+}
+
+impl<S> AsyncRead for StreamReader<S>
+where
+	S: Stream<Item = io::Result<Vec<u8>>> + Unpin,
+{
+	fn poll_read(
+		mut self: Pin<&mut Self>,
+		cx: &mut Context<'_>,
+		buf: &mut ReadBuf<'_>,
+	) -> Poll<io::Result<()>> {
+		if let Some((bytes, start)) = self.buffer.take_data() {
+			return self.buffer.put_data(buf, bytes, start);
+		}
+
+		loop {
+			match Pin::new(&mut self.stream).poll_next(cx) {
+				// An empty chunk tells `put_data` to wait for more data rather
+				// than signal EOF, so keep polling the stream ourselves instead
+				// of returning its `Pending` — otherwise we'd sleep with nothing
+				// left to wake us.
+				Poll::Ready(Some(Ok(bytes))) if bytes.is_empty() => continue,
+				Poll::Ready(Some(Ok(bytes))) => return self.buffer.put_data(buf, bytes, 0),
+				Poll::Ready(Some(Err(e))) => return Poll::Ready(Err(e)),
+				Poll::Ready(None) => return Poll::Ready(Ok(())),
+				Poll::Pending => return Poll::Pending,
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::{cell::RefCell, collections::VecDeque, io::Cursor, rc::Rc};
+
+	use futures::task::noop_waker_ref;
+
+	use super::*;
+
+	/// A `ReportCopyProgress` that records every call it receives, so tests
+	/// can assert on throttling/EOF behavior after the fact.
+	#[derive(Default, Clone)]
+	struct RecordingReporter(Rc<RefCell<Vec<(u64, u64)>>>);
+
+	impl ReportCopyProgress for RecordingReporter {
+		fn report_progress(&mut self, bytes_so_far: u64, total_bytes: u64) {
+			self.0.borrow_mut().push((bytes_so_far, total_bytes));
+		}
+	}
+
+	#[tokio::test]
+	async fn progress_read_always_reports_on_eof_but_not_before_the_interval() {
+		let recorder = RecordingReporter::default();
+		let mut reader = ProgressRead::new(Cursor::new(vec![0u8; 4]), 4, recorder.clone());
+
+		let mut buf = [0u8; 4];
+		// This read completes almost instantly, well under
+		// `PROGRESS_REPORT_INTERVAL`, so it shouldn't have reported yet.
+		let n = reader.read(&mut buf).await.unwrap();
+		assert_eq!(n, 4);
+		assert!(
+			recorder.0.borrow().is_empty(),
+			"shouldn't report before the throttle interval elapses"
+		);
+
+		// Hitting EOF must always report, regardless of how little time has
+		// passed since the last report.
+		let n = reader.read(&mut buf).await.unwrap();
+		assert_eq!(n, 0);
+		assert_eq!(recorder.0.borrow().as_slice(), &[(4, 4)]);
+	}
+
+	#[tokio::test]
+	async fn progress_read_reports_again_once_the_interval_elapses() {
+		let recorder = RecordingReporter::default();
+		let mut reader = ProgressRead::new(Cursor::new(vec![0u8; 8]), 8, recorder.clone());
+
+		let mut buf = [0u8; 4];
+		reader.read_exact(&mut buf).await.unwrap();
+		assert!(recorder.0.borrow().is_empty());
+
+		tokio::time::sleep(PROGRESS_REPORT_INTERVAL + Duration::from_millis(20)).await;
+
+		reader.read_exact(&mut buf).await.unwrap();
+		assert_eq!(recorder.0.borrow().as_slice(), &[(8, 8)]);
+	}
+
+	/// An `AsyncRead` that always fills the caller's buffer completely
+	/// (until `remaining` bytes have been doled out), recording how large
+	/// a read it was asked to satisfy each time. Used to drive
+	/// `copy_async_progress`'s adaptive buffer growth.
+	struct FullReader {
+		remaining: usize,
+		requested_lens: Rc<RefCell<Vec<usize>>>,
+	}
+
+	impl AsyncRead for FullReader {
+		fn poll_read(
+			mut self: Pin<&mut Self>,
+			_cx: &mut Context<'_>,
+			buf: &mut ReadBuf<'_>,
+		) -> Poll<io::Result<()>> {
+			let requested = buf.remaining();
+			self.requested_lens.borrow_mut().push(requested);
+
+			let n = std::cmp::min(self.remaining, requested);
+			self.remaining -= n;
+			buf.put_slice(&vec![0u8; n]);
+
+			Poll::Ready(Ok(()))
+		}
+	}
+
+	#[tokio::test]
+	async fn copy_async_progress_grows_the_buffer_on_full_reads_and_caps_it() {
+		let requested_lens = Rc::new(RefCell::new(Vec::new()));
+		let mut reader = FullReader {
+			remaining: 5_000_000,
+			requested_lens: requested_lens.clone(),
+		};
+		let mut writer = tokio::io::sink();
+
+		let copied = copy_async_progress(SilentCopyProgress(), &mut reader, &mut writer, 5_000_000)
+			.await
+			.unwrap();
+		assert_eq!(copied, 5_000_000);
+
+		let lens = requested_lens.borrow();
+		// Every full read should double the next requested size, starting
+		// from `INITIAL_COPY_BUF_SIZE`, until it hits the cap...
+		let expected_growth: Vec<usize> = (0..8)
+			.map(|i| std::cmp::min(INITIAL_COPY_BUF_SIZE << i, MAX_COPY_BUF_SIZE))
+			.collect();
+		assert_eq!(lens[..8], expected_growth[..]);
+		// ...after which it must never grow past the cap.
+		assert!(lens[8..].iter().all(|&l| l == MAX_COPY_BUF_SIZE));
+	}
+
+	#[tokio::test]
+	async fn copy_async_progress_does_not_grow_the_buffer_on_partial_reads() {
+		// Never fills the buffer, so the adaptive growth should never kick in.
+		let requested_lens = Rc::new(RefCell::new(Vec::new()));
+		let mut reader = FullReader {
+			remaining: 10,
+			requested_lens: requested_lens.clone(),
+		};
+		let mut writer = tokio::io::sink();
+
+		// `remaining` is far smaller than `INITIAL_COPY_BUF_SIZE`, so this is
+		// always a partial read relative to the buffer it's handed.
+		let copied = copy_async_progress(SilentCopyProgress(), &mut reader, &mut writer, 10)
+			.await
+			.unwrap();
+		assert_eq!(copied, 10);
+		assert!(requested_lens.borrow().iter().all(|&l| l == INITIAL_COPY_BUF_SIZE));
+	}
+
+	/// A `Stream<Item = io::Result<Vec<u8>>>` that yields a fixed, scripted
+	/// sequence of events, used to drive `StreamReader` through specific
+	/// poll outcomes (an empty chunk, a bare `Pending`, EOF) without a real
+	/// I/O source.
+	enum StreamEvent {
+		Pending,
+		Chunk(io::Result<Vec<u8>>),
+	}
+
+	struct ScriptedStream(VecDeque<StreamEvent>);
+
+	impl Stream for ScriptedStream {
+		type Item = io::Result<Vec<u8>>;
+
+		fn poll_next(mut self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+			match self.0.pop_front() {
+				None => Poll::Ready(None),
+				Some(StreamEvent::Pending) => Poll::Pending,
+				Some(StreamEvent::Chunk(item)) => Poll::Ready(Some(item)),
+			}
+		}
+	}
+
+	fn poll_read_once(
+		reader: &mut StreamReader<ScriptedStream>,
+		out: &mut [u8],
+	) -> Poll<io::Result<usize>> {
+		let mut cx = Context::from_waker(noop_waker_ref());
+		let mut read_buf = ReadBuf::new(out);
+		match Pin::new(reader).poll_read(&mut cx, &mut read_buf) {
+			Poll::Ready(Ok(())) => Poll::Ready(Ok(read_buf.filled().len())),
+			Poll::Ready(Err(e)) => Poll::Ready(Err(e)),
+			Poll::Pending => Poll::Pending,
+		}
+	}
+
+	#[test]
+	fn stream_reader_polls_through_empty_chunks() {
+		let stream = ScriptedStream(VecDeque::from([
+			StreamEvent::Chunk(Ok(vec![])),
+			StreamEvent::Chunk(Ok(vec![])),
+			StreamEvent::Chunk(Ok(vec![1, 2, 3])),
+		]));
+		let mut reader = StreamReader::new(stream);
+		let mut out = [0u8; 8];
+
+		// A single `poll_read` call must itself keep polling the stream past
+		// the empty chunks and return the real data — not stall waiting for
+		// a wakeup that the empty-chunk branch never registers.
+		match poll_read_once(&mut reader, &mut out) {
+			Poll::Ready(Ok(n)) => assert_eq!(&out[..n], &[1, 2, 3]),
+			other => panic!("expected a completed read past the empty chunks, got a {other:?}"),
+		}
+	}
+
+	#[test]
+	fn stream_reader_propagates_pending_from_the_stream() {
+		let stream = ScriptedStream(VecDeque::from([StreamEvent::Pending]));
+		let mut reader = StreamReader::new(stream);
+		let mut out = [0u8; 8];
+
+		// Unlike an empty chunk, a genuine `Pending` from the inner stream
+		// means a waker has already been registered there, so `poll_read`
+		// should return `Pending` without looping.
+		assert!(matches!(poll_read_once(&mut reader, &mut out), Poll::Pending));
+	}
+
+	#[tokio::test]
+	async fn bidirectional_progress_keeps_pumping_after_one_side_closes() {
+		let (mut a, mut a_remote) = tokio::io::duplex(64);
+		let (mut b, mut b_remote) = tokio::io::duplex(64);
+
+		let copy = tokio::spawn(async move {
+			copy_bidirectional_progress(&mut a, &mut b, SilentCopyProgress()).await
+		});
+
+		a_remote.write_all(b"hello").await.unwrap();
+		// Half-close: shut down a_remote's write side only, so a_to_b finishes
+		// and shuts down b, while b_to_a must keep running independently.
+		a_remote.shutdown().await.unwrap();
+
+		let mut received_from_a = [0u8; 5];
+		b_remote.read_exact(&mut received_from_a).await.unwrap();
+		assert_eq!(&received_from_a, b"hello");
+
+		b_remote.write_all(b"world!").await.unwrap();
+		b_remote.shutdown().await.unwrap();
+
+		let mut received_from_b = [0u8; 6];
+		a_remote.read_exact(&mut received_from_b).await.unwrap();
+		assert_eq!(&received_from_b, b"world!");
+
+		let (a_to_b, b_to_a) = copy.await.unwrap().unwrap();
+		assert_eq!(a_to_b, 5);
+		assert_eq!(b_to_a, 6);
+	}
+}